@@ -1,9 +1,14 @@
 use crate::{EntityRef, Module, ModuleTypes, TypeConvert};
-use std::{borrow::Cow, collections::HashMap, ops::Index};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ops::Index,
+};
 use wasmparser::{UnpackedIndex, Validator, ValidatorId};
 use wasmtime_types::{
     EngineOrModuleTypeIndex, ModuleInternedRecGroupIndex, ModuleInternedTypeIndex, TypeIndex,
-    WasmCompositeType, WasmFuncType, WasmHeapType, WasmResult, WasmSubType,
+    TypeTrace, WasmCompositeType, WasmFieldType, WasmFuncType, WasmHeapType, WasmRefType,
+    WasmResult, WasmStorageType, WasmStructType, WasmSubType, WasmValType,
 };
 
 /// A type marking the start of a recursion group's definition.
@@ -17,6 +22,70 @@ struct RecGroupStart {
     end: ModuleInternedTypeIndex,
 }
 
+/// An index identifying a canonical rec group within a shared, engine-wide
+/// [`EngineTypeRegistry`].
+///
+/// This is a plain, opaque handle: its only job is to let a
+/// [`ModuleTypesBuilder`] ask the registry for the engine-wide index
+/// corresponding to one of its members once the whole group has been
+/// canonicalized, via [`EngineTypeRegistry::engine_type_index`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct EngineRecGroupIndex(u32);
+
+impl EngineRecGroupIndex {
+    /// Create a new `EngineRecGroupIndex` from its raw index.
+    pub fn from_u32(index: u32) -> Self {
+        EngineRecGroupIndex(index)
+    }
+
+    /// Get the raw index of this `EngineRecGroupIndex`.
+    pub fn as_u32(&self) -> u32 {
+        self.0
+    }
+}
+
+/// The canonical, hashable form of a rec group, used to structurally
+/// deduplicate identical rec groups across modules that share the same
+/// engine.
+///
+/// Rec groups are atomic for canonicalization purposes: the canonical key
+/// is the *entire*, ordered group, not its individual members, since two
+/// groups only collapse to the same engine entry when every member matches
+/// lock-step. References from one member of the group to another are
+/// rewritten, before hashing, to a group-relative offset (still carried in
+/// an [`EngineOrModuleTypeIndex::Module`], but now indexing into this
+/// group rather than the whole module) so that the key is independent of
+/// wherever the group happened to land in a module's absolute type index
+/// space; references that escape the group must already be canonical
+/// engine indices by the time they're encoded here.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CanonicalRecGroup {
+    types: Box<[WasmSubType]>,
+}
+
+/// A shared, engine-wide registry of canonical rec groups.
+///
+/// This is implemented by the engine that owns a [`ModuleTypesBuilder`]'s
+/// eventual compiled types; it is expressed as a trait here, rather than a
+/// concrete type, so that `wasmtime-environ` doesn't need to depend on
+/// whatever crate implements the engine-wide registry.
+pub trait EngineTypeRegistry {
+    /// Look up an already-registered canonical rec group, if any.
+    fn lookup_canonical(&self, group: &CanonicalRecGroup) -> Option<EngineRecGroupIndex>;
+
+    /// Register a newly-seen canonical rec group, returning its new,
+    /// engine-wide index.
+    fn register_canonical(&mut self, group: CanonicalRecGroup) -> EngineRecGroupIndex;
+
+    /// Get the engine-wide index of the `offset`-th member of the given,
+    /// already-registered engine rec group.
+    fn engine_type_index(
+        &self,
+        group: EngineRecGroupIndex,
+        offset: u32,
+    ) -> EngineOrModuleTypeIndex;
+}
+
 /// A builder for [`ModuleTypes`].
 pub struct ModuleTypesBuilder {
     /// The ID of the validator that this builder is configured for. Using a
@@ -47,6 +116,17 @@ pub struct ModuleTypesBuilder {
     /// If we are in the middle of defining a recursion group, this is the
     /// metadata about the recursion group we started defining.
     defining_rec_group: Option<RecGroupStart>,
+
+    /// Rec groups we have already canonicalized and registered with an
+    /// [`EngineTypeRegistry`], so that `register_canonical` is idempotent.
+    canonicalized_rec_groups: HashMap<ModuleInternedRecGroupIndex, EngineRecGroupIndex>,
+
+    /// A map from module-interned type index to its engine-wide index, for
+    /// every type whose rec group has already been canonicalized. Used both
+    /// to resolve cross-rec-group references when canonicalizing later
+    /// groups, and to let `WasmparserTypeConverter` emit engine indices
+    /// directly once they're known.
+    module_index_to_engine: HashMap<ModuleInternedTypeIndex, EngineOrModuleTypeIndex>,
 }
 
 impl ModuleTypesBuilder {
@@ -59,6 +139,8 @@ impl ModuleTypesBuilder {
             wasmparser_to_wasmtime: HashMap::default(),
             already_seen: HashMap::default(),
             defining_rec_group: None,
+            canonicalized_rec_groups: HashMap::default(),
+            module_index_to_engine: HashMap::default(),
         }
     }
 
@@ -242,6 +324,71 @@ impl ModuleTypesBuilder {
         rec_group_index
     }
 
+    /// Canonicalize the given, already module-interned rec group and
+    /// register it with the engine-wide `engine_registry`, deduplicating it
+    /// against any other module's structurally identical rec group.
+    ///
+    /// Returns the shared, engine-wide index of the (possibly newly
+    /// registered) canonical rec group. Calling this more than once for the
+    /// same `rec_group` is cheap and returns the same index each time.
+    pub fn register_canonical(
+        &mut self,
+        engine_registry: &mut dyn EngineTypeRegistry,
+        rec_group: ModuleInternedRecGroupIndex,
+    ) -> EngineRecGroupIndex {
+        if let Some(idx) = self.canonicalized_rec_groups.get(&rec_group) {
+            return *idx;
+        }
+
+        let elems: Vec<_> = self.rec_group_elements(rec_group).collect();
+        let offset_of = |ty: ModuleInternedTypeIndex| elems.iter().position(|e| *e == ty);
+
+        let types = elems
+            .iter()
+            .map(|&ty| {
+                let mut sub_ty = self.types[ty].clone();
+                sub_ty
+                    .trace_mut::<_, ()>(&mut |index| {
+                        if let EngineOrModuleTypeIndex::Module(module_index) = *index {
+                            *index = match offset_of(module_index) {
+                                // A reference into this same rec group: keep
+                                // it module-relative, but now relative to
+                                // the start of *this group* rather than the
+                                // whole module, so the key is independent
+                                // of wherever the group landed.
+                                Some(offset) => EngineOrModuleTypeIndex::Module(
+                                    ModuleInternedTypeIndex::new(offset),
+                                ),
+                                // A reference outside of the group must
+                                // already be canonical: we canonicalize rec
+                                // groups in definition order, and
+                                // cross-group references can only point to
+                                // earlier, already-processed groups.
+                                None => self.module_index_to_engine[&module_index],
+                            };
+                        }
+                        Ok(())
+                    })
+                    .unwrap();
+                sub_ty
+            })
+            .collect();
+
+        let canon = CanonicalRecGroup { types };
+
+        let idx = engine_registry
+            .lookup_canonical(&canon)
+            .unwrap_or_else(|| engine_registry.register_canonical(canon));
+
+        for (offset, ty) in elems.iter().enumerate() {
+            self.module_index_to_engine
+                .insert(*ty, engine_registry.engine_type_index(idx, offset as u32));
+        }
+
+        self.canonicalized_rec_groups.insert(rec_group, idx);
+        idx
+    }
+
     /// Intern a type into this builder and get its Wasmtime index.
     ///
     /// This will intern not only the single given type, but the type's entire
@@ -324,6 +471,253 @@ impl ModuleTypesBuilder {
     pub fn trampoline_type(&self, ty: ModuleInternedTypeIndex) -> ModuleInternedTypeIndex {
         self.types.trampoline_type(ty)
     }
+
+    /// Does the interned type `a` match the interned type `b`?
+    ///
+    /// That is, is `a` a subtype of `b`? This implements the Wasm GC
+    /// `Matches` relation: func types are contravariant in their parameters
+    /// and covariant in their results; struct types use width-and-depth
+    /// subtyping; array types are covariant when immutable and invariant
+    /// when mutable.
+    pub fn matches(&self, a: ModuleInternedTypeIndex, b: ModuleInternedTypeIndex) -> bool {
+        let mut assumptions = HashSet::new();
+        self.sub_type_matches(a, b, &mut assumptions)
+    }
+
+    /// Does the heap type `a` match the heap type `b`?
+    ///
+    /// Same relation as [`Self::matches`], but for heap types, which may be
+    /// one of the abstract types (`any`, `eq`, `func`, ...) in addition to a
+    /// concrete interned type.
+    pub fn heap_type_matches(&self, a: &WasmHeapType, b: &WasmHeapType) -> bool {
+        let mut assumptions = HashSet::new();
+        self.heap_type_matches_impl(a, b, &mut assumptions)
+    }
+
+    /// Does `a <: b`, assuming that every pair in `assumptions` already
+    /// holds?
+    ///
+    /// Two types from the same rec group may reference each other, so we
+    /// can't simply recurse top-down: a naive implementation would loop
+    /// forever on such cycles. Instead, before recursing into a pair we
+    /// haven't seen yet we optimistically add it to `assumptions` and
+    /// coinductively treat it as holding if we ever see it again further
+    /// down the recursion. If that assumption turns out to be wrong, some
+    /// other, non-cyclic part of the comparison will still find the
+    /// mismatch and return `false`.
+    fn sub_type_matches(
+        &self,
+        a: ModuleInternedTypeIndex,
+        b: ModuleInternedTypeIndex,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        if a == b {
+            return true;
+        }
+
+        if !assumptions.insert((a, b)) {
+            return true;
+        }
+
+        let result = match (&self.types[a].composite_type, &self.types[b].composite_type) {
+            (WasmCompositeType::Func(a), WasmCompositeType::Func(b)) => {
+                self.func_type_matches(a, b, assumptions)
+            }
+            (WasmCompositeType::Struct(a), WasmCompositeType::Struct(b)) => {
+                self.struct_type_matches(a, b, assumptions)
+            }
+            (WasmCompositeType::Array(a), WasmCompositeType::Array(b)) => {
+                self.field_type_matches(&a.0, &b.0, assumptions)
+            }
+            // Funcs, structs, and arrays are never subtypes of one another.
+            (WasmCompositeType::Func(_), _)
+            | (WasmCompositeType::Struct(_), _)
+            | (WasmCompositeType::Array(_), _) => false,
+        };
+
+        assumptions.remove(&(a, b));
+        result
+    }
+
+    /// Implementation of `matches` for function types.
+    ///
+    /// Parameters are contravariant (`b`'s parameters must be subtypes of
+    /// `a`'s) and results are covariant.
+    fn func_type_matches(
+        &self,
+        a: &WasmFuncType,
+        b: &WasmFuncType,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        a.params().len() == b.params().len()
+            && a.returns().len() == b.returns().len()
+            && a.params()
+                .iter()
+                .zip(b.params())
+                .all(|(a, b)| self.val_type_matches(b, a, assumptions))
+            && a.returns()
+                .iter()
+                .zip(b.returns())
+                .all(|(a, b)| self.val_type_matches(a, b, assumptions))
+    }
+
+    /// Implementation of `matches` for struct types: width-and-depth
+    /// subtyping.
+    ///
+    /// The subtype must have at least as many fields as the supertype, and
+    /// each of the supertype's fields must be matched, in order, by the
+    /// subtype's corresponding field.
+    fn struct_type_matches(
+        &self,
+        a: &WasmStructType,
+        b: &WasmStructType,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        a.fields.len() >= b.fields.len()
+            && a.fields
+                .iter()
+                .zip(b.fields.iter())
+                .all(|(a, b)| self.field_type_matches(a, b, assumptions))
+    }
+
+    /// Implementation of `matches` for a single struct or array field.
+    ///
+    /// Mutable fields are invariant; immutable fields are covariant.
+    fn field_type_matches(
+        &self,
+        a: &WasmFieldType,
+        b: &WasmFieldType,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        if a.mutable != b.mutable {
+            return false;
+        }
+        if a.mutable {
+            a.element_type == b.element_type
+        } else {
+            self.storage_type_matches(&a.element_type, &b.element_type, assumptions)
+        }
+    }
+
+    /// Implementation of `matches` for storage types.
+    ///
+    /// Packed `i8`/`i16` storage has no subtyping of its own; only unpacked
+    /// value types can participate in covariance.
+    fn storage_type_matches(
+        &self,
+        a: &WasmStorageType,
+        b: &WasmStorageType,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        match (a, b) {
+            (WasmStorageType::Val(a), WasmStorageType::Val(b)) => {
+                self.val_type_matches(a, b, assumptions)
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Implementation of `matches` for value types.
+    fn val_type_matches(
+        &self,
+        a: &WasmValType,
+        b: &WasmValType,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        match (a, b) {
+            (WasmValType::Ref(a), WasmValType::Ref(b)) => {
+                self.ref_type_matches(a, b, assumptions)
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Implementation of `matches` for reference types.
+    ///
+    /// `a <: b` iff `a`'s heap type matches `b`'s heap type and, if `a` is
+    /// nullable, then `b` must be nullable too.
+    fn ref_type_matches(
+        &self,
+        a: &WasmRefType,
+        b: &WasmRefType,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        (!a.nullable || b.nullable)
+            && self.heap_type_matches_impl(&a.heap_type, &b.heap_type, assumptions)
+    }
+
+    /// Implementation of `matches` for heap types.
+    ///
+    /// Abstract heap types follow the fixed Wasm GC lattice: `none <: i31,
+    /// struct, array <: eq <: any` and `nofunc <: func`. Concrete
+    /// module-interned types match structurally, via `sub_type_matches`;
+    /// concrete types referenced by engine index match only by identity.
+    fn heap_type_matches_impl(
+        &self,
+        a: &WasmHeapType,
+        b: &WasmHeapType,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        use WasmHeapType::*;
+
+        if a == b {
+            return true;
+        }
+
+        match (a, b) {
+            (ConcreteFunc(a), ConcreteFunc(b))
+            | (ConcreteStruct(a), ConcreteStruct(b))
+            | (ConcreteArray(a), ConcreteArray(b)) => {
+                self.engine_or_module_type_matches(*a, *b, assumptions)
+            }
+
+            // Everything in the `any` hierarchy is a subtype of `any`.
+            (None | I31 | Eq | Struct | Array | ConcreteStruct(_) | ConcreteArray(_), Any) => true,
+            // `i31`, structs, and arrays (concrete or abstract) are subtypes
+            // of `eq`.
+            (None | I31 | Struct | Array | ConcreteStruct(_) | ConcreteArray(_), Eq) => true,
+            (None | ConcreteStruct(_), Struct) => true,
+            (None | ConcreteArray(_), Array) => true,
+            // `none` is the bottom of the `any` hierarchy. The `Eq`,
+            // `Struct`, `Array`, and `Any` cases are already covered by
+            // the arms above, so only `i31` and the concrete cases
+            // remain here.
+            (None, I31 | ConcreteStruct(_) | ConcreteArray(_)) => true,
+
+            // `nofunc` is the bottom of the `func` hierarchy.
+            (NoFunc, Func | ConcreteFunc(_)) => true,
+            (ConcreteFunc(_), Func) => true,
+
+            // `noextern` is the bottom of the `extern` hierarchy.
+            (NoExtern, Extern) => true,
+
+            _ => false,
+        }
+    }
+
+    /// Implementation of `matches` for indices that may refer either to a
+    /// type already registered with the engine, or to a type that is still
+    /// only known to this module.
+    fn engine_or_module_type_matches(
+        &self,
+        a: EngineOrModuleTypeIndex,
+        b: EngineOrModuleTypeIndex,
+        assumptions: &mut HashSet<(ModuleInternedTypeIndex, ModuleInternedTypeIndex)>,
+    ) -> bool {
+        match (a, b) {
+            (EngineOrModuleTypeIndex::Module(a), EngineOrModuleTypeIndex::Module(b)) => {
+                self.sub_type_matches(a, b, assumptions)
+            }
+            (EngineOrModuleTypeIndex::Engine(a), EngineOrModuleTypeIndex::Engine(b)) => a == b,
+            // A module-local index and an engine index match only when the
+            // module type's rec group has been canonicalized to exactly that
+            // engine index, i.e. they name the same type.
+            (EngineOrModuleTypeIndex::Module(m), e @ EngineOrModuleTypeIndex::Engine(_))
+            | (e @ EngineOrModuleTypeIndex::Engine(_), EngineOrModuleTypeIndex::Module(m)) => {
+                self.module_index_to_engine.get(&m) == Some(&e)
+            }
+        }
+    }
 }
 
 // Forward the indexing impl to the internal `ModuleTypes`
@@ -375,7 +769,15 @@ impl TypeConvert for WasmparserTypeConverter<'_> {
         match index {
             UnpackedIndex::Id(id) => {
                 let interned = self.types.wasmparser_to_wasmtime[&id];
-                let index = EngineOrModuleTypeIndex::Module(interned);
+                // Once a type's rec group has been canonicalized, prefer
+                // emitting its engine-wide index directly so downstream
+                // consumers get O(1) type equality for free.
+                let index = self
+                    .types
+                    .module_index_to_engine
+                    .get(&interned)
+                    .copied()
+                    .unwrap_or(EngineOrModuleTypeIndex::Module(interned));
 
                 // If this is a forward reference to a type in this type's rec
                 // group that we haven't converted yet, then we won't have an
@@ -403,7 +805,12 @@ impl TypeConvert for WasmparserTypeConverter<'_> {
             UnpackedIndex::Module(module_index) => {
                 let module_index = TypeIndex::from_u32(module_index);
                 let interned = self.module.types[module_index];
-                let index = EngineOrModuleTypeIndex::Module(interned);
+                let index = self
+                    .types
+                    .module_index_to_engine
+                    .get(&interned)
+                    .copied()
+                    .unwrap_or(EngineOrModuleTypeIndex::Module(interned));
 
                 // See comment above about `wasm_types` maybe not having the
                 // converted sub type yet. However in this case we don't have a
@@ -436,3 +843,719 @@ impl TypeConvert for WasmparserTypeConverter<'_> {
         }
     }
 }
+
+impl ModuleTypes {
+    /// Eliminate every type that is not transitively reachable from `roots`.
+    ///
+    /// This is the type-graph analogue of the `dce` subtest: `roots` should
+    /// be every type actually used by the module -- function signatures
+    /// referenced by defined or imported functions, table element types,
+    /// global types, tag types, and export or element-segment types -- and
+    /// this then marks every type transitively reachable from them through
+    /// composite-type field, parameter, result, and element heap-type
+    /// references. Because rec groups are atomic, if any member of a group
+    /// is live then the entire group is kept.
+    ///
+    /// Rebuilds `self` in place with a dense remapping from old to new
+    /// [`ModuleInternedTypeIndex`]es, preserving trampoline associations,
+    /// and returns that remapping so that callers (e.g. `Module`) can fix
+    /// up any indices they are holding onto.
+    pub fn eliminate_dead_types(
+        &mut self,
+        roots: impl IntoIterator<Item = ModuleInternedTypeIndex>,
+    ) -> HashMap<ModuleInternedTypeIndex, ModuleInternedTypeIndex> {
+        let live = self.reachable_types(roots);
+
+        // Rec groups are atomic: a single live member keeps the whole
+        // group alive.
+        let num_rec_groups = self.next_rec_group().index();
+        let mut live_rec_groups = Vec::new();
+        for i in 0..num_rec_groups {
+            let group = ModuleInternedRecGroupIndex::new(i);
+            let elems: Vec<_> = self.rec_group_elements(group).collect();
+            if elems.iter().any(|ty| live.contains(ty)) {
+                live_rec_groups.push(elems);
+            }
+        }
+
+        // Assign every surviving type its new, dense index first, in a pass
+        // that is entirely independent of reference resolution. This has to
+        // happen before we rewrite any references: a live type may have a
+        // forward reference to a later sibling within its own rec group
+        // (the mutually-recursive-types case this whole series exists to
+        // support), so that sibling's new index must already be known by
+        // the time we get to rewriting.
+        let mut remap = HashMap::new();
+        let mut next_new_index = 0u32;
+        for elems in &live_rec_groups {
+            for &old in elems {
+                remap.insert(old, ModuleInternedTypeIndex::new(next_new_index as usize));
+                next_new_index += 1;
+            }
+        }
+
+        // Now rebuild `self`, keeping only the live rec groups (in their
+        // original relative order), cloning each surviving type and
+        // rewriting its references using the now-fully-populated remap.
+        let mut new_types = ModuleTypes::default();
+        for elems in &live_rec_groups {
+            let start = new_types.next_ty();
+            for &old in elems {
+                let mut sub_ty = self[old].clone();
+                sub_ty
+                    .trace_mut::<_, ()>(&mut |index| {
+                        if let EngineOrModuleTypeIndex::Module(old_ref) = *index {
+                            *index = EngineOrModuleTypeIndex::Module(remap[&old_ref]);
+                        }
+                        Ok(())
+                    })
+                    .unwrap();
+                let new = new_types.push(sub_ty);
+                debug_assert_eq!(new, remap[&old]);
+            }
+            let end = new_types.next_ty();
+            new_types.push_rec_group(start..end);
+        }
+
+        // Trampoline associations reference types by index too, so they
+        // need to be re-established for the renumbered types. A live
+        // func type's trampoline was itself forced live by
+        // `reachable_types`, so if `old` survived then `old_trampoline`
+        // must have too.
+        for (old, old_trampoline) in self.trampoline_types() {
+            if let Some(&new) = remap.get(&old) {
+                new_types.set_trampoline_type(new, remap[&old_trampoline]);
+            }
+        }
+
+        *self = new_types;
+        remap
+    }
+
+    /// Compute the set of types transitively reachable from `roots`, via
+    /// composite-type field, parameter, result, and element heap-type
+    /// references, as well as the func-to-trampoline association (which
+    /// isn't part of the `TypeTrace` graph since it's tracked in a
+    /// separate side table). Every member of a reached type's rec group is
+    /// reached too, since the whole group is kept.
+    fn reachable_types(
+        &self,
+        roots: impl IntoIterator<Item = ModuleInternedTypeIndex>,
+    ) -> HashSet<ModuleInternedTypeIndex> {
+        let mut group_of = HashMap::new();
+        for i in 0..self.next_rec_group().index() {
+            let group = ModuleInternedRecGroupIndex::new(i);
+            for ty in self.rec_group_elements(group) {
+                group_of.insert(ty, group);
+            }
+        }
+
+        let mut live = HashSet::new();
+        let mut worklist: Vec<_> = roots.into_iter().collect();
+
+        while let Some(ty) = worklist.pop() {
+            if !live.insert(ty) {
+                continue;
+            }
+            if let Some(&group) = group_of.get(&ty) {
+                worklist.extend(self.rec_group_elements(group).filter(|t| !live.contains(t)));
+            }
+            if self[ty].is_func() {
+                let trampoline = self.trampoline_type(ty);
+                if !live.contains(&trampoline) {
+                    worklist.push(trampoline);
+                }
+            }
+            self[ty]
+                .trace::<_, ()>(&mut |index| {
+                    if let EngineOrModuleTypeIndex::Module(referenced) = index {
+                        if !live.contains(&referenced) {
+                            worklist.push(referenced);
+                        }
+                    }
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        live
+    }
+}
+
+/// An `arbitrary`-based generator of valid rec groups, used to
+/// differentially fuzz [`ModuleTypesBuilder::intern_rec_group`] and
+/// [`WasmparserTypeConverter::lookup_heap_type`]'s forward-reference
+/// handling against `wasmparser`'s own validator.
+///
+/// In the spirit of `wasm-smith`, this only ever produces rec groups that
+/// `wasmparser` accepts: heap-type references are drawn exclusively from
+/// types declared earlier in the module, from any member of the group
+/// currently being generated, including later ones (so that forward
+/// references and cycles within a rec group are exercised, but a reference
+/// can never escape forward out of the module entirely), or from the fixed
+/// abstract heap types.
+#[cfg(feature = "fuzzing")]
+pub mod arbitrary_rec_group {
+    use super::*;
+    use arbitrary::{Arbitrary, Unstructured};
+    use wasm_encoder::{
+        ArrayType, CompositeType, FieldType, FuncType, Module as EncodedModule, RefType,
+        StorageType, StructType, SubType, TypeSection, ValType as EncodedValType,
+    };
+
+    /// A single, randomly-but-validly generated rec group, encoded as a
+    /// standalone Wasm module consisting of nothing but the type section
+    /// declaring it (plus, when `count_earlier_types > 0`, the earlier
+    /// types it may refer to).
+    #[derive(Debug)]
+    pub struct ArbitraryRecGroup {
+        /// The number of types declared before this rec group, that this
+        /// rec group's members may refer to.
+        pub count_earlier_types: u32,
+        /// How many types are in the generated rec group.
+        pub len: u32,
+        /// The encoded Wasm module bytes: a single type section containing
+        /// `count_earlier_types` single-type rec groups followed by one
+        /// `len`-member rec group.
+        pub wasm: Vec<u8>,
+    }
+
+    impl<'a> Arbitrary<'a> for ArbitraryRecGroup {
+        fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+            let count_earlier_types = u.int_in_range(0..=4)?;
+            let len = u.int_in_range(1..=5)?;
+
+            let mut types = TypeSection::new();
+            for i in 0..count_earlier_types {
+                types.ty().func_type(&arbitrary_trivial_func_type(u, i)?);
+            }
+
+            // Every member of this rec group may refer to any other member
+            // of the *same* group, regardless of declaration order: that's
+            // what forces the forward references and cycles this generator
+            // exists to exercise. So the bound for composite-type
+            // references is the group's final size, not how far along
+            // we've gotten declaring it.
+            let final_max_ref = count_earlier_types + len - 1;
+
+            let mut group = Vec::with_capacity(usize::try_from(len).unwrap());
+            for i in 0..len {
+                let composite = arbitrary_composite_type(u, final_max_ref)?;
+
+                // Unlike ordinary heap-type references, a declared supertype
+                // must already exist by the time it's referenced, so it can
+                // only be one of the types declared strictly before this
+                // one.
+                let declared_so_far = count_earlier_types + i;
+                let supertype_idx = if u.ratio(1, 4)? && declared_so_far > 0 {
+                    Some(u.int_in_range(0..=(declared_so_far - 1))?)
+                } else {
+                    None
+                };
+                group.push(SubType {
+                    is_final: supertype_idx.is_none() || u.ratio(1, 2)?,
+                    supertype_idx,
+                    composite_type: composite,
+                });
+            }
+            types.ty().rec(group);
+
+            let mut module = EncodedModule::new();
+            module.section(&types);
+
+            Ok(ArbitraryRecGroup {
+                count_earlier_types,
+                len,
+                wasm: module.finish(),
+            })
+        }
+    }
+
+    /// Generate a trivial, self-contained `(func)` type, used to pad out
+    /// the "already declared" types that a generated rec group may refer
+    /// to.
+    fn arbitrary_trivial_func_type(
+        u: &mut Unstructured<'_>,
+        _index: u32,
+    ) -> arbitrary::Result<FuncType> {
+        let num_params = u.int_in_range(0..=3)?;
+        let num_results = u.int_in_range(0..=1)?;
+        Ok(FuncType::new(
+            std::iter::repeat(EncodedValType::I32).take(num_params),
+            std::iter::repeat(EncodedValType::I32).take(num_results),
+        ))
+    }
+
+    /// Generate an arbitrary func, struct, or array composite type whose
+    /// heap-type references only ever point at one of the first
+    /// `max_ref + 1` types of the module (by index `0..=max_ref`, which may
+    /// include rec group members not declared yet) or at an abstract heap
+    /// type.
+    fn arbitrary_composite_type(
+        u: &mut Unstructured<'_>,
+        max_ref: u32,
+    ) -> arbitrary::Result<CompositeType> {
+        Ok(match u.int_in_range(0..=2)? {
+            0 => CompositeType::Func(arbitrary_func_type(u, max_ref)?),
+            1 => CompositeType::Struct(arbitrary_struct_type(u, max_ref)?),
+            _ => CompositeType::Array(arbitrary_array_type(u, max_ref)?),
+        })
+    }
+
+    fn arbitrary_func_type(u: &mut Unstructured<'_>, max_ref: u32) -> arbitrary::Result<FuncType> {
+        let num_params = u.int_in_range(0..=4)?;
+        let num_results = u.int_in_range(0..=2)?;
+        let mut params = Vec::with_capacity(num_params);
+        for _ in 0..num_params {
+            params.push(arbitrary_val_type(u, max_ref)?);
+        }
+        let mut results = Vec::with_capacity(num_results);
+        for _ in 0..num_results {
+            results.push(arbitrary_val_type(u, max_ref)?);
+        }
+        Ok(FuncType::new(params, results))
+    }
+
+    fn arbitrary_struct_type(
+        u: &mut Unstructured<'_>,
+        max_ref: u32,
+    ) -> arbitrary::Result<StructType> {
+        let num_fields = u.int_in_range(0..=4)?;
+        let mut fields = Vec::with_capacity(num_fields);
+        for _ in 0..num_fields {
+            fields.push(arbitrary_field_type(u, max_ref)?);
+        }
+        Ok(StructType {
+            fields: fields.into_boxed_slice(),
+        })
+    }
+
+    fn arbitrary_array_type(
+        u: &mut Unstructured<'_>,
+        max_ref: u32,
+    ) -> arbitrary::Result<ArrayType> {
+        Ok(ArrayType(arbitrary_field_type(u, max_ref)?))
+    }
+
+    fn arbitrary_field_type(
+        u: &mut Unstructured<'_>,
+        max_ref: u32,
+    ) -> arbitrary::Result<FieldType> {
+        Ok(FieldType {
+            element_type: if u.ratio(1, 4)? {
+                if u.arbitrary()? {
+                    StorageType::I8
+                } else {
+                    StorageType::I16
+                }
+            } else {
+                StorageType::Val(arbitrary_val_type(u, max_ref)?)
+            },
+            mutable: u.arbitrary()?,
+        })
+    }
+
+    fn arbitrary_val_type(
+        u: &mut Unstructured<'_>,
+        max_ref: u32,
+    ) -> arbitrary::Result<EncodedValType> {
+        if u.ratio(1, 3)? {
+            return Ok(match u.int_in_range(0..=3)? {
+                0 => EncodedValType::I32,
+                1 => EncodedValType::I64,
+                2 => EncodedValType::F32,
+                _ => EncodedValType::F64,
+            });
+        }
+        Ok(EncodedValType::Ref(arbitrary_ref_type(u, max_ref)?))
+    }
+
+    /// Generate a reference type, drawing concrete references from
+    /// `0..=max_ref` *inclusive*, so that a member of a rec group can draw a
+    /// forward reference to a later sibling, or even a self-reference, and
+    /// not just to types declared strictly before it.
+    fn arbitrary_ref_type(u: &mut Unstructured<'_>, max_ref: u32) -> arbitrary::Result<RefType> {
+        let nullable = u.arbitrary()?;
+        if u.ratio(1, 2)? {
+            // An abstract heap type.
+            return Ok(match u.int_in_range(0..=5)? {
+                0 => RefType::ANYREF,
+                1 => RefType::EQREF,
+                2 => RefType::I31REF,
+                3 => RefType::STRUCTREF,
+                4 => RefType::ARRAYREF,
+                _ => RefType::NONEREF,
+            });
+        }
+        let index = u.int_in_range(0..=max_ref)?;
+        Ok(RefType {
+            nullable,
+            heap_type: wasm_encoder::HeapType::Concrete(index),
+        })
+    }
+
+    /// Feed a generated rec group through both `wasmparser`'s validator and
+    /// `ModuleTypesBuilder::intern_rec_group`, asserting the invariants
+    /// that make the interner trustworthy:
+    ///
+    /// - every func type ends up with an associated trampoline type,
+    /// - structurally identical trampolines are deduped to a single index,
+    /// - re-interning an identical rec group returns the same
+    ///   [`ModuleInternedRecGroupIndex`] rather than defining it twice, and
+    /// - looking up any of the generated forward references never panics.
+    pub fn check_intern_rec_group(generated: &ArbitraryRecGroup) -> WasmResult<()> {
+        let mut validator = Validator::new();
+        validator
+            .validate_all(&generated.wasm)
+            .expect("generator must only produce valid modules");
+
+        let types = validator.types(0).expect("module has finished validating");
+        let module = Module::default();
+        let mut builder = ModuleTypesBuilder::new(&validator);
+
+        let mut rec_group_id = None;
+        for id in types.core_type_ids() {
+            rec_group_id = Some(types.rec_group_id_of(id));
+        }
+        let rec_group_id = rec_group_id.expect("generator always emits at least one rec group");
+
+        let first = builder.intern_rec_group(&module, types, rec_group_id)?;
+        let second = builder.intern_rec_group(&module, types, rec_group_id)?;
+        assert_eq!(
+            first, second,
+            "re-interning an identical rec group must reuse its index"
+        );
+
+        let mut trampoline_by_signature: HashMap<WasmFuncType, ModuleInternedTypeIndex> =
+            HashMap::new();
+        for ty in builder.rec_group_elements(first) {
+            if builder[ty].is_func() {
+                let trampoline = builder.trampoline_type(ty);
+                assert!(
+                    builder.wasm_types().any(|(idx, _)| idx == trampoline),
+                    "every func type must have an associated trampoline type",
+                );
+
+                // Structurally identical trampolines must be deduped to a
+                // single index, rather than each func type getting its own
+                // copy.
+                let signature = builder[trampoline].unwrap_func().clone();
+                match trampoline_by_signature.get(&signature) {
+                    Some(&existing) => assert_eq!(
+                        existing, trampoline,
+                        "structurally identical trampolines must be deduped to one index",
+                    ),
+                    None => {
+                        trampoline_by_signature.insert(signature, trampoline);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> ModuleTypesBuilder {
+        ModuleTypesBuilder::new(&Validator::new())
+    }
+
+    fn push_func(
+        builder: &mut ModuleTypesBuilder,
+        params: Vec<WasmValType>,
+        results: Vec<WasmValType>,
+    ) -> ModuleInternedTypeIndex {
+        let idx = builder.types.push(WasmSubType {
+            composite_type: WasmCompositeType::Func(WasmFuncType::new(
+                params.into_boxed_slice(),
+                results.into_boxed_slice(),
+            )),
+        });
+        let next = builder.types.next_ty();
+        builder.types.push_rec_group(idx..next);
+        idx
+    }
+
+    /// Like [`push_func`], but also returns the rec group index of the
+    /// singleton group it was pushed into.
+    fn push_func_in_own_rec_group(
+        builder: &mut ModuleTypesBuilder,
+        params: Vec<WasmValType>,
+        results: Vec<WasmValType>,
+    ) -> (ModuleInternedTypeIndex, ModuleInternedRecGroupIndex) {
+        let idx = builder.types.push(WasmSubType {
+            composite_type: WasmCompositeType::Func(WasmFuncType::new(
+                params.into_boxed_slice(),
+                results.into_boxed_slice(),
+            )),
+        });
+        let next = builder.types.next_ty();
+        let rec_group = builder.types.push_rec_group(idx..next);
+        (idx, rec_group)
+    }
+
+    fn struct_referencing(referent: ModuleInternedTypeIndex) -> WasmSubType {
+        WasmSubType {
+            composite_type: WasmCompositeType::Struct(WasmStructType {
+                fields: Box::new([WasmFieldType {
+                    element_type: WasmStorageType::Val(WasmValType::Ref(WasmRefType {
+                        nullable: true,
+                        heap_type: WasmHeapType::ConcreteStruct(EngineOrModuleTypeIndex::Module(
+                            referent,
+                        )),
+                    })),
+                    mutable: false,
+                }]),
+            }),
+        }
+    }
+
+    #[test]
+    fn heap_type_matches_any_hierarchy() {
+        let b = builder();
+        assert!(b.heap_type_matches(&WasmHeapType::None, &WasmHeapType::Any));
+        assert!(b.heap_type_matches(&WasmHeapType::I31, &WasmHeapType::Eq));
+        // `structref` and `arrayref` must both be subtypes of `eqref`.
+        assert!(b.heap_type_matches(&WasmHeapType::Struct, &WasmHeapType::Eq));
+        assert!(b.heap_type_matches(&WasmHeapType::Array, &WasmHeapType::Eq));
+        assert!(b.heap_type_matches(&WasmHeapType::Struct, &WasmHeapType::Any));
+        assert!(b.heap_type_matches(&WasmHeapType::Array, &WasmHeapType::Any));
+        assert!(b.heap_type_matches(&WasmHeapType::None, &WasmHeapType::Struct));
+        assert!(!b.heap_type_matches(&WasmHeapType::Eq, &WasmHeapType::Struct));
+        assert!(!b.heap_type_matches(&WasmHeapType::Any, &WasmHeapType::Eq));
+        assert!(!b.heap_type_matches(&WasmHeapType::Func, &WasmHeapType::Any));
+    }
+
+    #[test]
+    fn heap_type_matches_func_hierarchy() {
+        let b = builder();
+        assert!(b.heap_type_matches(&WasmHeapType::NoFunc, &WasmHeapType::Func));
+        assert!(!b.heap_type_matches(&WasmHeapType::Func, &WasmHeapType::NoFunc));
+    }
+
+    #[test]
+    fn heap_type_matches_extern_hierarchy() {
+        let b = builder();
+        assert!(b.heap_type_matches(&WasmHeapType::NoExtern, &WasmHeapType::Extern));
+        assert!(!b.heap_type_matches(&WasmHeapType::Extern, &WasmHeapType::NoExtern));
+    }
+
+    #[test]
+    fn matches_func_is_contravariant_in_params_and_covariant_in_results() {
+        let mut b = builder();
+        let anyref = WasmValType::Ref(WasmRefType {
+            nullable: true,
+            heap_type: WasmHeapType::Any,
+        });
+        let eqref = WasmValType::Ref(WasmRefType {
+            nullable: true,
+            heap_type: WasmHeapType::Eq,
+        });
+
+        // `(func (param anyref) (result eqref))`
+        // <: `(func (param eqref) (result anyref))`
+        // because params are contravariant (a *subtype* may accept the
+        // wider `anyref` where its supertype accepts `eqref`) and results are covariant
+        // (returning the narrower `eqref` makes it a *subtype*'s result).
+        let narrower = push_func(&mut b, vec![anyref.clone()], vec![eqref.clone()]);
+        let wider = push_func(&mut b, vec![eqref], vec![anyref]);
+        assert!(b.matches(narrower, wider));
+        assert!(!b.matches(wider, narrower));
+    }
+
+    #[test]
+    fn matches_is_coinductive_on_mutually_recursive_structs() {
+        // Two struct types in the same rec group, each with a field that
+        // refers to the other. If the coinductive assumption didn't short
+        // circuit on the repeated `(a, b)` pair, this would recurse forever.
+        let mut b = builder();
+        let start = b.types.next_ty();
+        let a_idx = ModuleInternedTypeIndex::new(start.index());
+        let b_idx = ModuleInternedTypeIndex::new(start.index() + 1);
+
+        let a = b.types.push(struct_referencing(b_idx));
+        let bb = b.types.push(struct_referencing(a_idx));
+        let end = b.types.next_ty();
+        b.types.push_rec_group(a..end);
+
+        assert!(b.matches(a, bb));
+        assert!(b.matches(bb, a));
+    }
+
+    #[test]
+    fn eliminate_dead_types_drops_unreachable_groups() {
+        let mut b = builder();
+        let dead = push_func(&mut b, vec![], vec![]);
+        let live = push_func(&mut b, vec![], vec![]);
+        b.types.set_trampoline_type(live, live);
+
+        let mut types = b.finish();
+        let remap = types.eliminate_dead_types([live]);
+
+        assert!(!remap.contains_key(&dead));
+        assert!(remap.contains_key(&live));
+    }
+
+    #[test]
+    fn eliminate_dead_types_handles_forward_references_and_remaps_trampolines() {
+        let mut b = builder();
+
+        // An entirely unreachable rec group that should be dropped.
+        let dead = push_func(&mut b, vec![], vec![]);
+        b.types.set_trampoline_type(dead, dead);
+
+        // A live rec group of two mutually-referencing structs, where the
+        // root has a *forward* reference to its sibling.
+        let start = b.types.next_ty();
+        let live_a_idx = ModuleInternedTypeIndex::new(start.index());
+        let live_b_idx = ModuleInternedTypeIndex::new(start.index() + 1);
+        let live_a = b.types.push(struct_referencing(live_b_idx));
+        let live_b = b.types.push(struct_referencing(live_a_idx));
+        let end = b.types.next_ty();
+        b.types.push_rec_group(live_a..end);
+
+        // A live, standalone func type with a distinct trampoline type.
+        let live_func = push_func(&mut b, vec![], vec![]);
+        let trampoline = push_func(&mut b, vec![], vec![]);
+        b.types.set_trampoline_type(live_func, trampoline);
+        b.types.set_trampoline_type(trampoline, trampoline);
+
+        let mut types = b.finish();
+        // Note: `trampoline` is deliberately *not* passed as an explicit
+        // root here. It must be kept alive transitively, by virtue of
+        // being `live_func`'s trampoline.
+        let remap = types.eliminate_dead_types([live_a, live_func]);
+
+        assert!(!remap.contains_key(&dead));
+
+        // The forward reference survived renumbering (no panic above) and
+        // still points at the right sibling.
+        let new_a = remap[&live_a];
+        let new_b = remap[&live_b];
+        match &types[new_a].composite_type {
+            WasmCompositeType::Struct(s) => {
+                let WasmStorageType::Val(WasmValType::Ref(r)) = &s.fields[0].element_type else {
+                    panic!("expected a reference field");
+                };
+                assert_eq!(
+                    r.heap_type,
+                    WasmHeapType::ConcreteStruct(EngineOrModuleTypeIndex::Module(new_b)),
+                );
+            }
+            _ => panic!("expected a struct type"),
+        }
+
+        // Trampoline association survived renumbering too.
+        assert_eq!(
+            types.trampoline_type(remap[&live_func]),
+            remap[&trampoline]
+        );
+    }
+
+    /// A bare-bones [`EngineTypeRegistry`] for testing `register_canonical`
+    /// against, without pulling in a real engine. Its `EngineOrModuleTypeIndex`
+    /// values are a synthetic `group * 1000 + offset` encoding, good only for
+    /// distinguishing members of different (fake) registered groups in these
+    /// tests.
+    #[derive(Default)]
+    struct FakeEngineRegistry {
+        groups: Vec<CanonicalRecGroup>,
+        by_canon: HashMap<CanonicalRecGroup, EngineRecGroupIndex>,
+    }
+
+    impl EngineTypeRegistry for FakeEngineRegistry {
+        fn lookup_canonical(&self, group: &CanonicalRecGroup) -> Option<EngineRecGroupIndex> {
+            self.by_canon.get(group).copied()
+        }
+
+        fn register_canonical(&mut self, group: CanonicalRecGroup) -> EngineRecGroupIndex {
+            let idx = EngineRecGroupIndex::from_u32(u32::try_from(self.groups.len()).unwrap());
+            self.by_canon.insert(group.clone(), idx);
+            self.groups.push(group);
+            idx
+        }
+
+        fn engine_type_index(
+            &self,
+            group: EngineRecGroupIndex,
+            offset: u32,
+        ) -> EngineOrModuleTypeIndex {
+            EngineOrModuleTypeIndex::Module(ModuleInternedTypeIndex::new(
+                (group.as_u32() * 1000 + offset) as usize,
+            ))
+        }
+    }
+
+    #[test]
+    fn register_canonical_dedups_structurally_identical_groups_across_modules() {
+        let mut registry = FakeEngineRegistry::default();
+
+        let mut a = builder();
+        let (_, a_rec_group) = push_func_in_own_rec_group(&mut a, vec![], vec![]);
+
+        let mut b = builder();
+        let (_, b_rec_group) = push_func_in_own_rec_group(&mut b, vec![], vec![]);
+
+        let a_idx = a.register_canonical(&mut registry, a_rec_group);
+        let b_idx = b.register_canonical(&mut registry, b_rec_group);
+
+        assert_eq!(a_idx, b_idx);
+        assert_eq!(registry.groups.len(), 1);
+    }
+
+    #[test]
+    fn register_canonical_rewrites_internal_references_to_relative_offsets() {
+        let mut registry = FakeEngineRegistry::default();
+        let mut b = builder();
+
+        // A rec group of two mutually-referencing structs, so the second
+        // member's field is a *backward* reference to the first member and
+        // the first member's field is a *forward* reference to its sibling.
+        let start = b.types.next_ty();
+        let a_idx = ModuleInternedTypeIndex::new(start.index());
+        let b_idx = ModuleInternedTypeIndex::new(start.index() + 1);
+        let a = b.types.push(struct_referencing(b_idx));
+        let _bb = b.types.push(struct_referencing(a_idx));
+        let end = b.types.next_ty();
+        let rec_group = b.types.push_rec_group(a..end);
+
+        b.register_canonical(&mut registry, rec_group);
+
+        assert_eq!(registry.groups.len(), 1);
+        let canon = &registry.groups[0];
+        for (offset, referent) in [(0, 1u32), (1, 0u32)] {
+            match &canon.types[offset].composite_type {
+                WasmCompositeType::Struct(s) => {
+                    let WasmStorageType::Val(WasmValType::Ref(r)) = &s.fields[0].element_type
+                    else {
+                        panic!("expected a reference field");
+                    };
+                    assert_eq!(
+                        r.heap_type,
+                        WasmHeapType::ConcreteStruct(EngineOrModuleTypeIndex::Module(
+                            ModuleInternedTypeIndex::new(referent as usize)
+                        )),
+                    );
+                }
+                _ => panic!("expected a struct type"),
+            }
+        }
+    }
+
+    #[test]
+    fn register_canonical_is_idempotent() {
+        let mut registry = FakeEngineRegistry::default();
+        let mut b = builder();
+        let (_, rec_group) = push_func_in_own_rec_group(&mut b, vec![], vec![]);
+
+        let first = b.register_canonical(&mut registry, rec_group);
+        let second = b.register_canonical(&mut registry, rec_group);
+
+        assert_eq!(first, second);
+        assert_eq!(registry.groups.len(), 1);
+    }
+}